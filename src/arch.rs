@@ -0,0 +1,123 @@
+//! Target architecture selection.
+//!
+//! `uefi-run` originally hard-coded x86_64 (`qemu-system-x86_64`, `q35`,
+//! `OVMF.fd`, `BOOTX64.EFI`). This module centralizes the per-arch defaults
+//! so `--arch` can select aarch64 or riscv64 instead.
+
+use std::path::Path;
+use std::str::FromStr;
+
+/// Target architecture to run the EFI executable under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+    Riscv64,
+}
+
+impl Arch {
+    pub const VARIANTS: &'static [&'static str] = &["x86_64", "aarch64", "riscv64"];
+
+    /// Default `qemu-system-*` binary name for this architecture.
+    pub fn qemu_binary(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "qemu-system-x86_64",
+            Arch::Aarch64 => "qemu-system-aarch64",
+            Arch::Riscv64 => "qemu-system-riscv64",
+        }
+    }
+
+    /// Default `-machine` value.
+    pub fn default_machine(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "q35",
+            Arch::Aarch64 => "virt",
+            Arch::Riscv64 => "virt",
+        }
+    }
+
+    /// Default boot file name expected on the ESP at `/EFI/BOOT/<name>`.
+    pub fn boot_file_name(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "BOOTX64.EFI",
+            Arch::Aarch64 => "BOOTAA64.EFI",
+            Arch::Riscv64 => "BOOTRISCV64.EFI",
+        }
+    }
+
+    /// Well-known firmware file locations to probe, most distro-specific
+    /// first.
+    pub fn firmware_candidates(self) -> &'static [&'static str] {
+        match self {
+            Arch::X86_64 => &[
+                "/usr/share/OVMF/OVMF.fd",
+                "/usr/share/ovmf/x64/OVMF_CODE.fd",
+                "OVMF.fd",
+            ],
+            Arch::Aarch64 => &[
+                "/usr/share/AAVMF/AAVMF_CODE.fd",
+                "/usr/share/edk2/aarch64/QEMU_EFI.fd",
+                "AAVMF_CODE.fd",
+            ],
+            Arch::Riscv64 => &[
+                "/usr/share/edk2/riscv64/RISCV_VIRT_CODE.fd",
+                "RISCV_VIRT_CODE.fd",
+            ],
+        }
+    }
+
+    /// Find the first existing firmware candidate, if any.
+    pub fn find_firmware(self) -> Option<&'static str> {
+        self.firmware_candidates()
+            .iter()
+            .copied()
+            .find(|path| Path::new(path).exists())
+    }
+
+    /// Size the aarch64 CODE/VARS pflash units must be padded to. AAVMF
+    /// firmware is shipped as a pair of equally-sized flash images and
+    /// QEMU rejects a CODE image that doesn't match.
+    pub const AAVMF_FLASH_SIZE: u64 = 64 * 1024 * 1024;
+
+    /// Arch-specific `-cpu` default. `qemu-system-aarch64 -machine virt`
+    /// has no usable default CPU type and refuses to boot without one;
+    /// the other architectures are fine with qemu's own default.
+    pub fn default_cpu(self) -> Option<&'static str> {
+        match self {
+            Arch::Aarch64 => Some("max"),
+            Arch::X86_64 | Arch::Riscv64 => None,
+        }
+    }
+
+    /// `-drive`/`-device` arguments to attach the ESP image. `virt`
+    /// machines have no IDE bus, so a bare `format=raw,file=...` (which
+    /// defaults to `if=ide`) fails there; attach over virtio instead.
+    /// x86_64's q35 keeps the original IDE-backed drive.
+    pub fn esp_drive_args(self, image_path: &Path) -> Vec<String> {
+        match self {
+            Arch::X86_64 => vec![
+                "-drive".into(),
+                format!("format=raw,file={}", image_path.display()),
+            ],
+            Arch::Aarch64 | Arch::Riscv64 => vec![
+                "-drive".into(),
+                format!("if=none,format=raw,id=esp,file={}", image_path.display()),
+                "-device".into(),
+                "virtio-blk-pci,drive=esp".into(),
+            ],
+        }
+    }
+}
+
+impl FromStr for Arch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x86_64" => Ok(Arch::X86_64),
+            "aarch64" => Ok(Arch::Aarch64),
+            "riscv64" => Ok(Arch::Riscv64),
+            other => Err(format!("Unknown architecture `{}`", other)),
+        }
+    }
+}
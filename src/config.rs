@@ -0,0 +1,46 @@
+//! Declarative run configuration, loaded from a TOML file via `--config`
+//! and merged with CLI overrides (CLI flags always win).
+//!
+//! This lets a project commit a reproducible `run.toml` describing its
+//! VM instead of encoding a long qemu invocation in a shell script.
+
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// On-disk representation of a `--config run.toml` file. Every field is
+/// optional: a config only needs to pin down the parts that matter for a
+/// project, everything else falls back to uefi-run's usual defaults or a
+/// CLI override.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub bios: Option<String>,
+    pub qemu: Option<String>,
+    pub arch: Option<String>,
+    pub machine: Option<String>,
+    pub memory: Option<String>,
+    pub cpu: Option<String>,
+    #[serde(default)]
+    pub add_file: Vec<String>,
+    #[serde(default)]
+    pub qemu_args: Vec<String>,
+    pub exit_device: Option<bool>,
+    pub timeout: Option<u64>,
+    pub capture_serial: Option<String>,
+    pub success_pattern: Option<String>,
+    pub failure_pattern: Option<String>,
+    pub qmp: Option<String>,
+}
+
+impl Config {
+    /// Load and parse a config file, panicking with a descriptive message
+    /// on missing file or invalid TOML.
+    pub fn load(path: &Path) -> Config {
+        let text = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Unable to read config file {}: {}", path.display(), e));
+        toml::from_str(&text)
+            .unwrap_or_else(|e| panic!("Unable to parse config file {}: {}", path.display(), e))
+    }
+}
@@ -0,0 +1,122 @@
+//! Building the ESP (EFI System Partition) as a real FAT32 disk image.
+//!
+//! QEMU's `fat:rw:` pseudo-filesystem driver is convenient but fragile: it
+//! can corrupt state on writes and has awkward size limits. Instead we
+//! format an actual disk image with [`fatfs`] and hand that to QEMU as a
+//! raw `-drive` (see `Arch::esp_drive_args`).
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use fatfs::{Dir, FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use fscommon::BufStream;
+
+/// Size of the generated ESP image. FAT32 requires at least 65525 data
+/// clusters, which a 32 MiB volume falls just short of once reserved
+/// sectors and FAT overhead are accounted for; 64 MiB clears that
+/// boundary with room to spare while still formatting quickly.
+const IMAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A `host_path:esp_path` pair staged into the ESP via `--add-file`.
+#[derive(Debug, Clone)]
+pub struct StagedFile {
+    pub host_path: PathBuf,
+    /// Path inside the ESP without a leading slash, e.g. `startup.nsh`.
+    pub esp_path: String,
+}
+
+impl FromStr for StagedFile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, esp) = s.split_once(':').ok_or_else(|| {
+            format!(
+                "Invalid --add-file `{}`, expected format host_path:esp_path",
+                s
+            )
+        })?;
+        if host.is_empty() || esp.is_empty() {
+            return Err(format!(
+                "Invalid --add-file `{}`, expected format host_path:esp_path",
+                s
+            ));
+        }
+        Ok(StagedFile {
+            host_path: PathBuf::from(host),
+            esp_path: esp.trim_start_matches('/').replace('\\', "/"),
+        })
+    }
+}
+
+/// Create a FAT32 disk image at `image_path` containing `efi_exe` as
+/// `/EFI/BOOT/{boot_file_name}`, plus any `staged_files`.
+pub fn create_esp_image(
+    image_path: &Path,
+    efi_exe: &Path,
+    boot_file_name: &str,
+    staged_files: &[StagedFile],
+) -> io::Result<()> {
+    let image_file = File::create(image_path)?;
+    image_file.set_len(IMAGE_SIZE)?;
+
+    let mut storage = BufStream::new(image_file);
+    fatfs::format_volume(
+        &mut storage,
+        FormatVolumeOptions::new().fat_type(FatType::Fat32),
+    )
+    .map_err(to_io_error)?;
+
+    let fs = FileSystem::new(storage, FsOptions::new()).map_err(to_io_error)?;
+    let root_dir = fs.root_dir();
+
+    let efi_boot_dir = create_dir_all(&root_dir, "EFI/BOOT")?;
+    copy_file_into(&efi_boot_dir, efi_exe, boot_file_name)?;
+
+    for staged in staged_files {
+        let (dir_path, file_name) = match staged.esp_path.rsplit_once('/') {
+            Some((dir, name)) => (dir, name),
+            None => ("", staged.esp_path.as_str()),
+        };
+        let dir = if dir_path.is_empty() {
+            root_dir.clone()
+        } else {
+            create_dir_all(&root_dir, dir_path)?
+        };
+        copy_file_into(&dir, &staged.host_path, file_name)?;
+    }
+
+    Ok(())
+}
+
+/// Create `path` (e.g. `"EFI/BOOT"`) inside `root`, creating any missing
+/// intermediate directories, and return the final directory.
+fn create_dir_all<'a>(
+    root: &Dir<'a, BufStream<File>>,
+    path: &str,
+) -> io::Result<Dir<'a, BufStream<File>>> {
+    let mut dir = root.clone();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        dir = match dir.create_dir(component) {
+            Ok(d) => d,
+            Err(fatfs::Error::AlreadyExists) => dir.open_dir(component).map_err(to_io_error)?,
+            Err(e) => return Err(to_io_error(e)),
+        };
+    }
+    Ok(dir)
+}
+
+fn copy_file_into(dir: &Dir<BufStream<File>>, host_path: &Path, file_name: &str) -> io::Result<()> {
+    let mut src = File::open(host_path)?;
+    let mut dst = dir.create_file(file_name).map_err(to_io_error)?;
+    io::copy(&mut src, &mut dst)?;
+    Ok(())
+}
+
+fn to_io_error(e: fatfs::Error<io::Error>) -> io::Error {
+    match e {
+        fatfs::Error::Io(e) => e,
+        other => io::Error::new(io::ErrorKind::Other, other.to_string()),
+    }
+}
@@ -1,10 +1,24 @@
-use std::path::Path;
-use std::process::{Child, Command, exit};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Child, Command, ExitStatus};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use wait_timeout::ChildExt;
 
+mod arch;
+mod config;
+mod disk_image;
+mod qmp;
+mod serial;
+
+use arch::Arch;
+use config::Config;
+use disk_image::StagedFile;
+use qmp::QmpConnection;
+use serial::PatternMatch;
+
 fn main() {
     let matches = clap::App::new("uefi-run")
         .version(env!("CARGO_PKG_VERSION"))
@@ -22,7 +36,7 @@ fn main() {
             clap::Arg::with_name("bios_path")
                 .value_name("bios_path")
                 .required(false)
-                .help("BIOS image (default = /usr/share/OVMF/{OVMF.fd, x64/OVMF_CODE.fd} or ./OVMF.fd)")
+                .help("BIOS image (default = arch-specific search paths)")
                 .short("b")
                 .long("bios"),
         )
@@ -30,10 +44,81 @@ fn main() {
             clap::Arg::with_name("qemu_path")
                 .value_name("qemu_path")
                 .required(false)
-                .help("Path to qemu executable (default = qemu-system-x86_64)")
+                .help("Path to qemu executable (default = qemu-system-<arch>)")
                 .short("q")
                 .long("qemu"),
         )
+        .arg(
+            clap::Arg::with_name("arch")
+                .value_name("arch")
+                .required(false)
+                .help("Target architecture to run (default = x86_64)")
+                .long("arch")
+                .possible_values(Arch::VARIANTS),
+        )
+        .arg(
+            clap::Arg::with_name("config")
+                .value_name("path")
+                .required(false)
+                .help("TOML file describing the run environment; CLI flags override it")
+                .long("config"),
+        )
+        .arg(
+            clap::Arg::with_name("add_file")
+                .value_name("host_path:esp_path")
+                .required(false)
+                .help("Additional file to place on the ESP, e.g. startup.nsh:/startup.nsh")
+                .long("add-file")
+                .number_of_values(1)
+                .multiple(true),
+        )
+        .arg(
+            clap::Arg::with_name("exit_device")
+                .required(false)
+                .help(
+                    "Add an isa-debug-exit device and translate its exit code: a guest \
+                     writing V to port 0xf4 becomes uefi-run exit code V (0x10 means success)",
+                )
+                .long("exit-device"),
+        )
+        .arg(
+            clap::Arg::with_name("timeout")
+                .value_name("secs")
+                .required(false)
+                .help("Kill qemu if it hasn't exited after this many seconds")
+                .long("timeout"),
+        )
+        .arg(
+            clap::Arg::with_name("capture_serial")
+                .value_name("file")
+                .required(false)
+                .help("Redirect the guest serial output to a file instead of stdio")
+                .long("capture-serial"),
+        )
+        .arg(
+            clap::Arg::with_name("success_pattern")
+                .value_name("regex")
+                .required(false)
+                .help("Exit 0 as soon as a line of serial output matches this regex")
+                .long("success-pattern"),
+        )
+        .arg(
+            clap::Arg::with_name("failure_pattern")
+                .value_name("regex")
+                .required(false)
+                .help("Exit non-zero as soon as a line of serial output matches this regex")
+                .long("failure-pattern"),
+        )
+        .arg(
+            clap::Arg::with_name("qmp")
+                .value_name("path")
+                .required(false)
+                .help(
+                    "Unix socket path for a QMP control connection, used for orderly shutdown \
+                     and guest lifecycle events instead of a raw kill",
+                )
+                .long("qmp"),
+        )
         .arg(
             clap::Arg::with_name("qemu_args")
                 .value_name("qemu_args")
@@ -43,29 +128,89 @@ fn main() {
         )
         .get_matches();
 
-    // Parse options
+    // Parse options, merging the optional --config file underneath any
+    // CLI flag: CLI always wins.
+    let config = matches
+        .value_of("config")
+        .map(|path| Config::load(Path::new(path)))
+        .unwrap_or_default();
+
     let efi_exe = matches.value_of("efi_exe").unwrap();
-    let bios_path = matches.value_of("bios_path").unwrap_or_else(|| {
-        // Debian Ubuntu
-        if Path::new("/usr/share/OVMF/OVMF.fd").exists() {
-            "/usr/share/OVMF/OVMF.fd"
-        // Archlinux
-        } else if Path::new("/usr/share/ovmf/x64/OVMF_CODE.fd").exists() {
-            "/usr/share/ovmf/x64/OVMF_CODE.fd"
-        } else if Path::new("OVMF.fd").exists() {
-            "OVMF.fd"
-        } else {
-            eprintln!("Unable to find OVMF.fd");
+    let arch: Arch = matches
+        .value_of("arch")
+        .or(config.arch.as_deref())
+        .unwrap_or("x86_64")
+        .parse()
+        .unwrap_or_else(|e: String| {
+            eprintln!("{}", e);
             exit(1);
-        }
-    });
-    dbg!(bios_path);
+        });
+    let bios_path = matches
+        .value_of("bios_path")
+        .or(config.bios.as_deref())
+        .unwrap_or_else(|| {
+            arch.find_firmware().unwrap_or_else(|| {
+                eprintln!("Unable to find firmware for {:?}", arch);
+                exit(1);
+            })
+        });
     let qemu_path = matches
         .value_of("qemu_path")
-        .unwrap_or("qemu-system-x86_64");
-    let user_qemu_args = matches
-        .values_of("qemu_args")
-        .unwrap_or(clap::Values::default());
+        .or(config.qemu.as_deref())
+        .unwrap_or_else(|| arch.qemu_binary());
+    let user_qemu_args: Vec<String> = config
+        .qemu_args
+        .iter()
+        .cloned()
+        .chain(
+            matches
+                .values_of("qemu_args")
+                .unwrap_or(clap::Values::default())
+                .map(String::from),
+        )
+        .collect();
+    let staged_files: Vec<StagedFile> = config
+        .add_file
+        .iter()
+        .map(String::as_str)
+        .chain(
+            matches
+                .values_of("add_file")
+                .unwrap_or(clap::Values::default()),
+        )
+        .map(|s| {
+            s.parse().unwrap_or_else(|e: String| {
+                eprintln!("{}", e);
+                exit(1);
+            })
+        })
+        .collect();
+    let exit_device = matches.is_present("exit_device") || config.exit_device.unwrap_or(false);
+    let timeout = match matches.value_of("timeout") {
+        Some(s) => Some(s.parse::<u64>().unwrap_or_else(|_| {
+            eprintln!("Invalid --timeout `{}`, expected a number of seconds", s);
+            exit(1);
+        })),
+        None => config.timeout,
+    };
+    let capture_serial = matches
+        .value_of("capture_serial")
+        .map(String::from)
+        .or(config.capture_serial.clone())
+        .map(PathBuf::from);
+    let success_pattern = matches
+        .value_of("success_pattern")
+        .or(config.success_pattern.as_deref())
+        .map(parse_regex);
+    let failure_pattern = matches
+        .value_of("failure_pattern")
+        .or(config.failure_pattern.as_deref())
+        .map(parse_regex);
+    let qmp_path = matches
+        .value_of("qmp")
+        .map(String::from)
+        .or(config.qmp.clone())
+        .map(PathBuf::from);
 
     // Install termination signal handler. This ensures that the destructor of
     // `temp_dir` which is constructed in the next step is really called and
@@ -81,33 +226,124 @@ fn main() {
         .expect("Error setting termination handler");
     }
 
-    // Create temporary dir for ESP.
+    // Build the ESP as a real FAT32 disk image rather than relying on
+    // QEMU's fragile `fat:rw:` pseudo-filesystem.
     let temp_dir = tempfile::tempdir().expect("Unable to create temporary directory");
-    // Path to /EFI/BOOT
-    let efi_boot_path = temp_dir.path().join("EFI").join("BOOT");
-    std::fs::create_dir_all(efi_boot_path.clone()).expect("Unable to create /EFI/BOOT directory");
-    let bootx64_path = efi_boot_path.join("BOOTX64.EFI");
-    std::fs::copy(efi_exe, bootx64_path).expect("Unable to copy EFI executable");
+    let image_path = temp_dir.path().join("disk.img");
+    disk_image::create_esp_image(
+        &image_path,
+        Path::new(efi_exe),
+        arch.boot_file_name(),
+        &staged_files,
+    )
+    .expect("Unable to create ESP disk image");
 
+    // Decide where the guest serial output goes. Pattern matching needs a
+    // real file to tail even if the user didn't ask to keep it around.
+    let serial_file = capture_serial.or_else(|| {
+        if success_pattern.is_some() || failure_pattern.is_some() {
+            Some(temp_dir.path().join("serial.log"))
+        } else {
+            None
+        }
+    });
+    let match_slot = if success_pattern.is_some() || failure_pattern.is_some() {
+        Some(serial::spawn_pattern_matcher(
+            serial_file.clone().unwrap(),
+            success_pattern,
+            failure_pattern,
+        ))
+    } else {
+        None
+    };
+
+    let machine = config.machine.clone().unwrap_or_else(|| {
+        if arch == Arch::X86_64 {
+            "q35,accel=kvm:tcg".to_string()
+        } else {
+            arch.default_machine().to_string()
+        }
+    });
     let qemu_args_ref = vec![
         // Disable default devices.
         // QEMU by defaults enables a ton of devices which slow down boot.
         "-nodefaults",
         // Use a modern machine, with acceleration if possible.
-        "-machine","q35,accel=kvm:tcg",
+        "-machine",
+        &machine,
         // A standard VGA card with Bochs VBE extensions.
-        "-vga","std",
+        "-vga",
+        "std",
+    ];
+    let mut qemu_args: Vec<_> = qemu_args_ref.into_iter().map(|x| x.into()).collect();
+    if let Some(memory) = &config.memory {
+        qemu_args.push("-m".into());
+        qemu_args.push(memory.clone());
+    }
+    if let Some(cpu) = config
+        .cpu
+        .clone()
+        .or_else(|| arch.default_cpu().map(String::from))
+    {
+        qemu_args.push("-cpu".into());
+        qemu_args.push(cpu);
+    }
+    qemu_args.push("-serial".into());
+    match &serial_file {
         // Connect the serial port to the host. OVMF is kind enough to connect
         // the UEFI stdout and stdin to that port too.
-        "-serial","stdio",
-        // Set up OVMF.
-        "-bios",bios_path,
-        // Mount a local directory as a FAT partition.
-        "-drive",
-    ];
-    let mut qemu_args:Vec<_> = qemu_args_ref.into_iter().map(|x| x.into()).collect();
-    qemu_args.push(format!("format=raw,file=fat:rw:{}", temp_dir.path().display()));
-    qemu_args.extend(user_qemu_args.map(|x| x.into()));
+        None => qemu_args.push("stdio".into()),
+        Some(path) => qemu_args.push(format!("file:{}", path.display())),
+    }
+    match arch {
+        Arch::X86_64 => {
+            qemu_args.push("-bios".into());
+            qemu_args.push(bios_path.into());
+        }
+        Arch::Riscv64 => {
+            // `virt` otherwise boots its own built-in OpenSBI payload,
+            // which conflicts with the EDK2 pflash image below.
+            qemu_args.push("-bios".into());
+            qemu_args.push("none".into());
+            qemu_args.push("-drive".into());
+            qemu_args.push(format!(
+                "if=pflash,format=raw,readonly=on,file={}",
+                bios_path
+            ));
+        }
+        Arch::Aarch64 => {
+            // AAVMF needs a CODE/VARS pflash pair, both padded to the
+            // same size; a lone read-only CODE unit refuses to boot.
+            let code_path = temp_dir.path().join("flash0.fd");
+            let vars_path = temp_dir.path().join("flash1.fd");
+            prepare_padded_flash(Path::new(bios_path), &code_path, Arch::AAVMF_FLASH_SIZE)
+                .expect("Unable to prepare AAVMF CODE flash image");
+            create_zeroed_flash(&vars_path, Arch::AAVMF_FLASH_SIZE)
+                .expect("Unable to prepare AAVMF VARS flash image");
+            qemu_args.push("-drive".into());
+            qemu_args.push(format!(
+                "if=pflash,format=raw,unit=0,readonly=on,file={}",
+                code_path.display()
+            ));
+            qemu_args.push("-drive".into());
+            qemu_args.push(format!(
+                "if=pflash,format=raw,unit=1,file={}",
+                vars_path.display()
+            ));
+        }
+    }
+    qemu_args.extend(arch.esp_drive_args(&image_path));
+    if exit_device {
+        // Lets the guest signal a pass/fail exit code by writing to port
+        // 0xf4; decoded from QEMU's own exit status below.
+        qemu_args.push("-device".into());
+        qemu_args.push("isa-debug-exit,iobase=0xf4,iosize=0x04".into());
+    }
+    if let Some(path) = &qmp_path {
+        qemu_args.push("-qmp".into());
+        qemu_args.push(format!("unix:{},server,nowait", path.display()));
+    }
+    qemu_args.extend(user_qemu_args);
 
     // Run qemu.
     let mut child = Command::new(qemu_path)
@@ -115,27 +351,59 @@ fn main() {
         .spawn()
         .expect("Failed to start qemu");
 
-    // Wait for qemu to exit or signal.
-    let mut child_terminated;
+    let mut qmp_conn = qmp_path.as_deref().map(|path| {
+        QmpConnection::connect(path, Duration::from_secs(5))
+            .expect("Unable to establish QMP connection")
+    });
+
+    // Wait for qemu to exit, a signal, a timeout, a pattern match, or a
+    // guest shutdown/reset/panic reported over QMP.
+    let deadline = timeout.map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut child_terminated: Option<ExitStatus>;
     loop {
         child_terminated = wait_qemu(&mut child, Duration::from_millis(500));
-        if child_terminated || terminating.load(Ordering::SeqCst) {
+        if child_terminated.is_some() || terminating.load(Ordering::SeqCst) {
+            break;
+        }
+        if deadline.map_or(false, |d| Instant::now() >= d) {
+            println!("uefi-run: timeout reached, terminating qemu...");
+            break;
+        }
+        if match_slot
+            .as_ref()
+            .map_or(false, |s| s.lock().unwrap().is_some())
+        {
+            break;
+        }
+        if qmp_conn.as_mut().map_or(false, |q| q.poll_events()) {
             break;
         }
     }
 
     // If uefi-run received a signal we still need the child to exit.
-    if !child_terminated {
-        child_terminated = wait_qemu(&mut child, Duration::from_secs(1));
-        if !child_terminated {
+    if child_terminated.is_none() {
+        // Prefer an orderly QMP shutdown over a raw kill when available.
+        if let Some(qmp_conn) = &mut qmp_conn {
+            if qmp_conn.shutdown().is_ok() {
+                child_terminated = wait_qemu(&mut child, Duration::from_secs(2));
+            }
+        }
+        if child_terminated.is_none() {
+            child_terminated = wait_qemu(&mut child, Duration::from_secs(1));
+        }
+        if child_terminated.is_none() {
             match child.kill() {
                 // Kill succeeded
-                Ok(_) => assert!(wait_qemu(&mut child, Duration::from_secs(1))),
+                Ok(_) => {
+                    child_terminated = wait_qemu(&mut child, Duration::from_secs(1));
+                    assert!(child_terminated.is_some())
+                }
                 Err(e) => {
                     match e.kind() {
                         // Not running anymore
                         std::io::ErrorKind::InvalidInput => {
-                            assert!(wait_qemu(&mut child, Duration::from_secs(1)))
+                            child_terminated = wait_qemu(&mut child, Duration::from_secs(1));
+                            assert!(child_terminated.is_some())
                         }
                         // Other error
                         _ => panic!("Not able to kill child process: {:?}", e),
@@ -144,19 +412,90 @@ fn main() {
             }
         }
     }
+
+    // `exit` skips destructors, so remove the temporary files first.
+    if let Some(pattern_match) = match_slot.and_then(|s| *s.lock().unwrap()) {
+        drop(temp_dir);
+        exit(match pattern_match {
+            PatternMatch::Success => 0,
+            PatternMatch::Failure => 1,
+        });
+    }
+
+    if exit_device {
+        drop(temp_dir);
+        exit(decode_exit_device_status(child_terminated.unwrap()));
+    }
+}
+
+/// Parse a `--success-pattern`/`--failure-pattern` value, exiting with a
+/// usage error on invalid regex syntax.
+fn parse_regex(s: &str) -> regex::Regex {
+    regex::Regex::new(s).unwrap_or_else(|e| {
+        eprintln!("Invalid regex `{}`: {}", s, e);
+        exit(1);
+    })
+}
+
+/// Decode QEMU's process exit status into the guest-requested exit code,
+/// per the isa-debug-exit convention: a guest write of `V` to port 0xf4
+/// makes QEMU exit with status `(V << 1) | 1`, so an odd status `c` maps
+/// back to guest code `c >> 1`. The customary success sentinel `0x10`
+/// maps to `0`.
+fn decode_exit_device_status(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(c) if c % 2 == 1 => {
+            let guest_code = c >> 1;
+            if guest_code == 0x10 {
+                0
+            } else {
+                guest_code
+            }
+        }
+        Some(c) => {
+            eprintln!(
+                "qemu exited with status {} which isa-debug-exit could not have produced",
+                c
+            );
+            1
+        }
+        None => {
+            eprintln!("qemu did not exit normally");
+            1
+        }
+    }
+}
+
+/// Copy `src` into `dest` and pad it with zeroes up to `size`, as QEMU
+/// expects for a pflash CODE unit.
+fn prepare_padded_flash(src: &Path, dest: &Path, size: u64) -> io::Result<()> {
+    let mut input = fs::File::open(src)?;
+    let mut output = fs::File::create(dest)?;
+    io::copy(&mut input, &mut output)?;
+    output.set_len(size)?;
+    Ok(())
+}
+
+/// Create an empty, zero-filled flash image for a writable pflash VARS
+/// unit.
+fn create_zeroed_flash(dest: &Path, size: u64) -> io::Result<()> {
+    let file = fs::File::create(dest)?;
+    file.set_len(size)?;
+    Ok(())
 }
 
 /// Wait for the process to exit for `duration`.
 ///
-/// Returns `true` if the process exited and false if the timeout expired.
-fn wait_qemu(child: &mut Child, duration: Duration) -> bool {
+/// Returns `Some(exit_status)` if the process exited, `None` if the
+/// timeout expired first.
+fn wait_qemu(child: &mut Child, duration: Duration) -> Option<ExitStatus> {
     let wait_result = child
         .wait_timeout(duration)
         .expect("Failed to wait on child process");
     match wait_result {
         None => {
             // Child still alive.
-            return false;
+            None
         }
         Some(exit_status) => {
             // Child exited.
@@ -166,7 +505,7 @@ fn wait_qemu(child: &mut Child, duration: Duration) -> bool {
                     None => println!("qemu exited unsuccessfully"),
                 }
             }
-            return true;
+            Some(exit_status)
         }
     }
 }
@@ -0,0 +1,101 @@
+//! QMP (QEMU Machine Protocol) control socket integration.
+//!
+//! When `--qmp <path>` is given, uefi-run connects to QEMU's QMP unix
+//! socket (`-qmp unix:<path>,server,nowait`) to perform an orderly
+//! shutdown (`system_powerdown` then `quit`) instead of a raw
+//! `SIGKILL`, and to log guest lifecycle events.
+
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use qapi::{qmp, Qmp};
+
+/// A connected, capabilities-negotiated QMP session.
+///
+/// The socket stays in blocking mode so the synchronous `handshake`/
+/// `execute` calls get proper request/reply semantics. `poll_events`
+/// instead toggles a short read timeout just for its own call so it
+/// can't block the caller's wait loop, then restores blocking mode for
+/// any later command.
+pub struct QmpConnection {
+    control: UnixStream,
+    qmp: Qmp<qapi::Stream<io::BufReader<UnixStream>, UnixStream>>,
+}
+
+impl QmpConnection {
+    /// Connect to the QMP unix socket at `path`, retrying for up to
+    /// `timeout` while QEMU is still starting up, and complete the
+    /// capabilities handshake.
+    pub fn connect(path: &Path, timeout: Duration) -> io::Result<Self> {
+        let deadline = Instant::now() + timeout;
+        let stream = loop {
+            match UnixStream::connect(path) {
+                Ok(s) => break s,
+                Err(e) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(50));
+                    let _ = e;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        let mut qmp = Qmp::from_stream(qapi::Stream::new(
+            io::BufReader::new(stream.try_clone()?),
+            stream.try_clone()?,
+        ));
+        qmp.handshake()?;
+        Ok(QmpConnection {
+            control: stream,
+            qmp,
+        })
+    }
+
+    /// Ask the guest to power down, then quit qemu. This gives the guest
+    /// firmware a chance to shut down cleanly, unlike `child.kill()`.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        // Commands need a blocking reply read; undo any timeout left
+        // over from a `poll_events` call.
+        self.control.set_read_timeout(None)?;
+        self.qmp.execute(&qmp::system_powerdown {})?;
+        self.qmp.execute(&qmp::quit {})?;
+        Ok(())
+    }
+
+    /// Drain and log any pending QMP events. Returns `true` if the guest
+    /// reported shutting down, resetting or panicking, in which case the
+    /// caller should stop waiting on the qemu process.
+    pub fn poll_events(&mut self) -> bool {
+        // A short read timeout, rather than a fully non-blocking socket,
+        // lets this return promptly when there's nothing to read without
+        // ever tripping a WouldBlock mid-handshake or mid-command.
+        if self
+            .control
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .is_err()
+        {
+            return false;
+        }
+        let mut should_stop = false;
+        while let Some(event) = self.qmp.next_event() {
+            match event {
+                qmp::Event::SHUTDOWN { .. } => {
+                    println!("qmp: guest SHUTDOWN");
+                    should_stop = true;
+                }
+                qmp::Event::RESET { .. } => {
+                    println!("qmp: guest RESET");
+                    should_stop = true;
+                }
+                qmp::Event::GUEST_PANICKED { .. } => {
+                    println!("qmp: guest GUEST_PANICKED");
+                    should_stop = true;
+                }
+                _ => {}
+            }
+        }
+        let _ = self.control.set_read_timeout(None);
+        should_stop
+    }
+}
@@ -0,0 +1,85 @@
+//! Serial output capture and pattern-based pass/fail detection for CI.
+//!
+//! When `--success-pattern`/`--failure-pattern` are given, the guest's
+//! serial output (redirected to a file via `-serial file:<path>`) is
+//! tailed line-by-line on a background thread so uefi-run can decide
+//! pass/fail without waiting for QEMU itself to exit.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use regex::Regex;
+
+/// Outcome reported by the pattern-matching tail thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternMatch {
+    Success,
+    Failure,
+}
+
+/// Shared slot the tail thread writes its verdict into.
+pub type MatchSlot = Arc<Mutex<Option<PatternMatch>>>;
+
+/// Spawn a thread that tails `path` for new lines and records the first
+/// pattern match (if any) into the returned slot.
+pub fn spawn_pattern_matcher(
+    path: PathBuf,
+    success_pattern: Option<Regex>,
+    failure_pattern: Option<Regex>,
+) -> MatchSlot {
+    let slot: MatchSlot = Arc::new(Mutex::new(None));
+    let slot_thread = slot.clone();
+    thread::spawn(move || {
+        // Qemu may not have created the file yet.
+        let file = loop {
+            match File::open(&path) {
+                Ok(f) => break f,
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+        let mut reader = BufReader::new(file);
+        // Accumulates a line across reads; a plain file reports EOF (and
+        // thus `read_line` returns) as soon as the writer pauses, even
+        // mid-line, so a pattern must only be evaluated once the buffer
+        // actually ends in a newline.
+        let mut line = String::new();
+        loop {
+            match reader.read_line(&mut line) {
+                Ok(0) => {
+                    // Caught up with the writer; wait for more data.
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Ok(_) if !line.ends_with('\n') => {
+                    // Partial line; the rest is still being written.
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Ok(_) => {
+                    let found = if failure_pattern
+                        .as_ref()
+                        .map_or(false, |re| re.is_match(&line))
+                    {
+                        Some(PatternMatch::Failure)
+                    } else if success_pattern
+                        .as_ref()
+                        .map_or(false, |re| re.is_match(&line))
+                    {
+                        Some(PatternMatch::Success)
+                    } else {
+                        None
+                    };
+                    if let Some(found) = found {
+                        *slot_thread.lock().unwrap() = Some(found);
+                        return;
+                    }
+                    line.clear();
+                }
+                Err(_) => return,
+            }
+        }
+    });
+    slot
+}